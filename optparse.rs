@@ -220,6 +220,29 @@ impl Uint: Value {
     }
 }
 
+struct ChoiceData {
+    allowed: ~[~str],
+    value: @mut @str,
+}
+
+type Choice = @ChoiceData;
+
+impl Choice: Value {
+    fn set(s: &str) -> Option<~str> {
+        for self.allowed.each |a| {
+            if *a == s.to_owned() {
+                *self.value = s.to_managed();
+                return None;
+            }
+        }
+        Some(fmt!("invalid value '%s': expected one of %s", s,
+                  str::connect(self.allowed, ", ")))
+    }
+    fn string() -> ~str {
+        fmt!("\"%s\"", *self.value)
+    }
+}
+
 pub type ErrPrinter = &fn(&str, &str);
 
 fn default_arg_required(prog: &str, arg: &str) {
@@ -235,12 +258,16 @@ fn default_required(prog: &str, arg: &str) {
 }
 
 pub struct OptionParser {
+    mut add_completion: bool,
     mut add_help: bool,
     mut add_version: bool,
+    mut config_path: ~str,
+    mut prog: ~str,
     mut err_arg_required: ErrPrinter,
     mut err_no_such_option: ErrPrinter,
     mut err_required: ErrPrinter,
     mut next_dest: ~str,
+    mut next_env: ~str,
     mut next_multi: bool,
     mut next_required: bool,
     mut opts: ~[@OptValue],
@@ -265,11 +292,141 @@ impl OptionParser {
         val
     }
 
+    fn choice(&self, flags: &[&str], info: &str, allowed: &[&str],
+              default: &str) -> @mut @str {
+        let val = @mut default.to_managed();
+        let mut owned = ~[];
+        for allowed.each |a| { owned.push(str::from_slice(*a)); }
+        let choice = @ChoiceData{ allowed: copy owned, value: val };
+        self.option(flags, info, false, choice as Value);
+        // Stash the permitted set so `print_help` can advertise it.
+        let opt = self.opts[self.opts.len() - 1];
+        opt.allowed = move owned;
+        val
+    }
+
+    fn config_file(&self, path: &str) -> &self/OptionParser {
+        self.config_path = str::from_slice(path);
+        return self;
+    }
+
+    // Read a simple INI-style config file and feed each recognised entry
+    // through the matching option's `Value::set`. A `[section]` header
+    // qualifies the keys beneath it, so both `key` and `section.key` are
+    // matched against the `conf:` flag registered for an option.
+    priv fn load_config(&self, path: &str) {
+        let data = match io::read_whole_file_str(path) {
+            Ok(move s) => s,
+            Err(_) => return
+        };
+        let mut section = ~"";
+        for str::each_line(data) |raw| {
+            let line = raw.trim();
+            if line.len() == 0 || line.starts_with("#") || line.starts_with(";") {
+                loop;
+            }
+            if line.starts_with("[") && line.ends_with("]") {
+                section = line.slice(1, line.len() - 1).trim().to_owned();
+                loop;
+            }
+            match str::find_char(line, '=') {
+                Some(pos) => {
+                    let key = line.slice(0, pos).trim().to_owned();
+                    let val = line.slice(pos + 1, line.len()).trim().to_owned();
+                    let full = if section.len() != 0 {
+                        section + "." + key
+                    } else {
+                        copy key
+                    };
+                    for self.opts.each |opt| {
+                        if opt.conf.len() != 0 {
+                            let name = opt.conf.slice(0, opt.conf.len() - 1).to_owned();
+                            if name == full || name == key {
+                                self.apply(*opt, val);
+                            }
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+
+    priv fn basename(&self, path: &str) -> ~str {
+        match str::rfind_char(path, '/') {
+            Some(pos) => path.slice(pos + 1, path.len()).to_owned(),
+            None => str::from_slice(path)
+        }
+    }
+
+    priv fn prog_name(&self) -> ~str {
+        if self.prog.len() != 0 { copy self.prog } else { ~"prog" }
+    }
+
+    // Emit a shell-completion script derived from the registered options.
+    // Value options offer filename completion for their argument while
+    // implicit (bool) flags complete as plain words.
+    fn print_completion(&self, shell: &str) {
+        let prog = self.prog_name();
+        match shell {
+            "bash" => {
+                let mut flags = ~[];
+                for self.opts.each |opt| {
+                    if opt.flag_short.len() != 0 { flags.push(copy opt.flag_short); }
+                    if opt.flag_long.len() != 0 { flags.push(copy opt.flag_long); }
+                }
+                io::println(fmt!("_%s() {", prog));
+                io::println("    local cur prev");
+                io::println("    cur=\"${COMP_WORDS[COMP_CWORD]}\"");
+                io::println("    prev=\"${COMP_WORDS[COMP_CWORD-1]}\"");
+                io::println("    case \"$prev\" in");
+                for self.opts.each |opt| {
+                    if !opt.implicit {
+                        let mut pats = ~[];
+                        if opt.flag_short.len() != 0 { pats.push(copy opt.flag_short); }
+                        if opt.flag_long.len() != 0 { pats.push(copy opt.flag_long); }
+                        io::println(fmt!("        %s)", str::connect(pats, "|")));
+                        io::println("            COMPREPLY=( $(compgen -f -- \"$cur\") )");
+                        io::println("            return 0");
+                        io::println("            ;;");
+                    }
+                }
+                io::println("    esac");
+                io::println(fmt!("    COMPREPLY=( $(compgen -W \"%s\" -- \"$cur\") )",
+                                 str::connect(flags, " ")));
+                io::println("    return 0");
+                io::println("}");
+                io::println(fmt!("complete -F _%s %s", prog, prog));
+            }
+            "zsh" => {
+                io::println(fmt!("#compdef %s", prog));
+                io::println("_arguments \\");
+                let mut lines = ~[];
+                for self.opts.each |opt| {
+                    let suffix = if opt.implicit { ~"" } else { ~":arg:_files" };
+                    if opt.flag_short.len() != 0 {
+                        lines.push(fmt!("  '%s[%s]%s'", opt.flag_short, opt.info, suffix));
+                    }
+                    if opt.flag_long.len() != 0 {
+                        lines.push(fmt!("  '%s[%s]%s'", opt.flag_long, opt.info, suffix));
+                    }
+                }
+                io::println(str::connect(lines, " \\\n"));
+            }
+            _ => io::println(fmt!("optparse: unsupported shell: %s", shell))
+        }
+    }
+
     fn dest(&self, name: &str) -> &self/OptionParser {
         self.next_dest = str::from_slice(name);
         return self;
     }
 
+    fn env(&self, name: &str) -> &self/OptionParser {
+        self.next_env = str::from_slice(name);
+        return self;
+    }
+
     fn i64(&self, flags: &[&str], info: &str) -> @mut i64 {
         self._i64(flags, info, 0)
     }
@@ -359,8 +516,10 @@ impl OptionParser {
             }
         }
         self.opts.push(@OptValue{
+            allowed: ~[],
             defined: false,
             dest: copy self.next_dest,
+            env_name: copy self.next_env,
             flag_long: move flag_long,
             flag_short: move flag_short,
             implicit: implicit,
@@ -376,6 +535,7 @@ impl OptionParser {
             conf: move conf,
         });
         self.next_dest = ~"";
+        self.next_env = ~"";
         self.next_multi = false;
         self.next_required = false;
     }
@@ -388,30 +548,167 @@ impl OptionParser {
         self._parse(args)
     }
 
+    priv fn apply(&self, opt: @OptValue, arg: &str) {
+        match opt.value.set(arg) {
+            None => opt.defined = true,
+            Some(_) => {}
+        }
+    }
+
+    priv fn find_long(&self, flag: &str) -> Option<@OptValue> {
+        for self.opts.each |opt| {
+            if opt.flag_long.len() != 0 && opt.flag_long == flag.to_owned() {
+                return Some(*opt);
+            }
+        }
+        None
+    }
+
+    priv fn find_short(&self, flag: &str) -> Option<@OptValue> {
+        for self.opts.each |opt| {
+            if opt.flag_short.len() != 0 && opt.flag_short == flag.to_owned() {
+                return Some(*opt);
+            }
+        }
+        None
+    }
+
     priv fn _parse(&self, args: &[~str]) -> ~[~str] {
-        let retargs: ~[~str] = ~[];
+        let mut retargs: ~[~str] = ~[];
         let arglen = args.len();
-        let optslen = self.opts.len();
-        let mut i = 0;
+        let prog = if arglen != 0 { self.basename(args[0]) } else { ~"" };
+        self.prog = copy prog;
+        // Config-file values are the lowest-precedence source of truth: they
+        // override the compiled-in defaults but are themselves overridden by
+        // any flag seen on the command line below.
+        if self.config_path.len() != 0 {
+            self.load_config(self.config_path);
+        }
+        // Environment variables sit between the config file and the command
+        // line in the precedence chain: CLI flag > env var > config > default.
+        // Applying them here lets the argument loop below override them.
+        for self.opts.each |opt| {
+            if opt.env_name.len() != 0 {
+                match os::getenv(opt.env_name) {
+                    Some(ref v) => self.apply(*opt, *v),
+                    None => {}
+                }
+            }
+        }
+        let mut i = 1;
+        let mut only_args = false;
         while i < arglen {
             let arg = copy args[i];
-            let mut j = 0;
-            while j < optslen {
-                let opt = self.opts[j];
-                if opt.flag_long == arg {
-                    if opt.implicit {
-                        opt.value.set(arg);
-                    } else if arglen > i + 1 {
-                        opt.value.set(args[i+1]);
+            // Everything after a standalone "--" is a plain parameter.
+            if only_args {
+                retargs.push(move arg);
+                i += 1;
+                loop;
+            }
+            if arg == ~"--" {
+                only_args = true;
+                i += 1;
+                loop;
+            }
+            if arg.starts_with("--") {
+                // Long option, honouring the "--flag=value" syntax.
+                let mut name = copy arg;
+                let mut inline: Option<~str> = None;
+                match str::find_char(arg, '=') {
+                    Some(pos) => {
+                        name = str::slice(arg, 0, pos).to_owned();
+                        inline = Some(str::slice(arg, pos + 1, arg.len()).to_owned());
+                    }
+                    None => {}
+                }
+                // Built-in "--completion <shell>" flag, when enabled.
+                if self.add_completion && name == ~"--completion" {
+                    let shell = match inline {
+                        Some(ref v) => copy *v,
+                        None => if i + 1 < arglen {
+                            let s = copy args[i + 1];
+                            i += 1;
+                            s
+                        } else {
+                            ~""
+                        }
+                    };
+                    self.print_completion(shell);
+                    os::set_exit_status(0);
+                    return move retargs;
+                }
+                match self.find_long(name) {
+                    Some(opt) => {
+                        if opt.implicit {
+                            self.apply(opt, name);
+                        } else {
+                            match inline {
+                                Some(ref v) => { self.apply(opt, *v); }
+                                None => {
+                                    if i + 1 < arglen {
+                                        self.apply(opt, args[i + 1]);
+                                        i += 1;
+                                    } else {
+                                        (self.err_arg_required)(prog, name);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    None => (self.err_no_such_option)(prog, name)
+                }
+            } else if arg.starts_with("-") && arg.len() > 1 {
+                // One or more clustered short options, e.g. "-vp" or "-ofoo".
+                let n = arg.len();
+                let mut k = 1;
+                while k < n {
+                    let flag = ~"-" + str::slice(arg, k, k + 1).to_owned();
+                    match self.find_short(flag) {
+                        Some(opt) => {
+                            if opt.implicit {
+                                self.apply(opt, flag);
+                                k += 1;
+                            } else {
+                                // A value option ends the cluster; its
+                                // argument is either the remaining characters
+                                // ("-ofoo") or the following token ("-o foo").
+                                if k + 1 < n {
+                                    self.apply(opt, str::slice(arg, k + 1, n));
+                                } else if i + 1 < arglen {
+                                    self.apply(opt, args[i + 1]);
+                                    i += 1;
+                                } else {
+                                    (self.err_arg_required)(prog, flag);
+                                }
+                                break;
+                            }
+                        }
+                        None => {
+                            (self.err_no_such_option)(prog, flag);
+                            break;
+                        }
                     }
-                    break;
                 }
-                j += 1;
+            } else {
+                // A genuine non-option parameter.
+                retargs.push(move arg);
             }
             i += 1;
         }
-        self.add_help = false;
-        self.add_version = false;
+        // Every option flagged as required must have received a value from the
+        // command line, an environment variable or the config file; complain
+        // and set a non-zero exit status for the ones missing.
+        for self.opts.each |opt| {
+            if (opt.required_flag || opt.required_conf) && !opt.defined {
+                let flag = if opt.flag_long.len() != 0 {
+                    copy opt.flag_long
+                } else {
+                    copy opt.flag_short
+                };
+                (self.err_required)(prog, flag);
+                os::set_exit_status(1);
+            }
+        }
         move retargs
     }
 
@@ -419,6 +716,114 @@ impl OptionParser {
         io::println(name)
     }
 
+    priv fn term_width(&self) -> uint {
+        match os::getenv("COLUMNS") {
+            Some(ref s) => match uint::from_str(*s) {
+                Some(w) if w > 0 => w,
+                _ => 80
+            },
+            None => 80
+        }
+    }
+
+    priv fn format_flags(&self, opt: @OptValue) -> ~str {
+        let mut parts = ~[];
+        if opt.flag_short.len() != 0 { parts.push(copy opt.flag_short); }
+        if opt.flag_long.len() != 0 { parts.push(copy opt.flag_long); }
+        let mut s = ~"  " + str::connect(parts, ", ");
+        if !opt.implicit {
+            let arg = if opt.dest.len() != 0 {
+                str::to_upper(opt.dest)
+            } else {
+                ~"ARG"
+            };
+            s += " ";
+            s += arg;
+        }
+        move s
+    }
+
+    priv fn format_info(&self, opt: @OptValue) -> ~str {
+        let mut s = copy opt.info;
+        if opt.allowed.len() != 0 {
+            s += fmt!(" (one of: %s)", str::connect(opt.allowed, ", "));
+        }
+        if opt.env_name.len() != 0 {
+            s += fmt!(" [env: %s]", opt.env_name);
+        }
+        move s
+    }
+
+    // Greedily wrap `text` to `avail` columns, indenting every continuation
+    // line by `indent` spaces so they line up under the first character of
+    // the description. Words are never split across lines.
+    priv fn wrap(&self, text: &str, avail: uint, indent: uint) -> ~str {
+        let words = str::words(text);
+        if words.len() == 0 {
+            return ~"";
+        }
+        let pad = str::repeat(" ", indent);
+        let mut out = ~"";
+        let mut line = ~"";
+        for words.each |w| {
+            if line.len() == 0 {
+                line = str::from_slice(*w);
+            } else if line.len() + 1 + w.len() <= avail {
+                line += " ";
+                line += *w;
+            } else {
+                out += line;
+                out += "\n";
+                out += pad;
+                line = str::from_slice(*w);
+            }
+        }
+        out += line;
+        move out
+    }
+
+    fn print_help(&self) {
+        if self.usage.len() != 0 {
+            io::println(copy self.usage);
+        }
+        io::println("");
+
+        let mut lefts = ~[];
+        let mut infos = ~[];
+        for self.opts.each |opt| {
+            lefts.push(self.format_flags(*opt));
+            infos.push(self.format_info(*opt));
+        }
+        if self.add_help {
+            lefts.push(~"  -h, --help");
+            infos.push(~"show this help message and exit");
+        }
+        if self.add_version {
+            lefts.push(~"  -v, --version");
+            infos.push(~"show program's version number and exit");
+        }
+
+        // Align every description under a column two spaces past the widest
+        // flag entry.
+        let mut width = 0;
+        for lefts.each |l| {
+            if l.len() > width { width = l.len(); }
+        }
+        let indent = width + 2;
+        let term = self.term_width();
+        let avail = if term > indent { term - indent } else { 20 };
+
+        let n = lefts.len();
+        let mut i = 0;
+        while i < n {
+            let left = copy lefts[i];
+            let pad = str::repeat(" ", indent - left.len());
+            let wrapped = self.wrap(infos[i], avail, indent);
+            io::println(left + pad + wrapped);
+            i += 1;
+        }
+    }
+
     fn required(&self) -> &self/OptionParser {
         self.next_required = true;
         return self;
@@ -471,9 +876,11 @@ impl OptionParser {
 }
 
 struct OptValue {
+    mut allowed: ~[~str],
     conf: ~str,
     mut defined: bool,
     dest: ~str,
+    env_name: ~str,
     flag_long: ~str,
     flag_short: ~str,
     implicit: bool,
@@ -486,7 +893,10 @@ struct OptValue {
 
 pub fn new(usage: ~str, version: ~str) -> ~OptionParser {
     ~OptionParser{
+        add_completion: false,
         add_help: true,
+        config_path: ~"",
+        prog: ~"",
         add_version: if version == ~"" {
             false
         } else {
@@ -496,6 +906,7 @@ pub fn new(usage: ~str, version: ~str) -> ~OptionParser {
         err_no_such_option: default_no_such_option,
         err_required: default_required,
         next_dest: ~"",
+        next_env: ~"",
         next_multi: false,
         next_required: false,
         opts: ~[],